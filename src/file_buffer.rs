@@ -1,81 +1,158 @@
 use std::io::prelude::*;
-use std::io::{self, ErrorKind, SeekFrom};
-use std::collections::HashMap;
+use std::io::{self, ErrorKind, SeekFrom, IoSlice, IoSliceMut};
 use std::cmp;
+use std::str;
+use std::ops::Range;
+use std::fs::File;
+#[cfg(feature = "mmap")]
+use std::any::Any;
 
 use lru_cache::LruCache;
+#[cfg(feature = "mmap")]
+use memmap2::{MmapMut, MmapOptions};
 
-/// Slab size MUST be a power of 2!
-const SLAB_SIZE: usize = 512*1024; // Change this number to change the SLAB_SIZE (currently @ 512kb)
-
-/// Used to turn a file index into an array index (since SLAB_SIZE is a power of two,
-/// subtracting one from it will yield all ones, and anding it with a number will
-/// yield only the lowest n bits, where SLAB_SIZE = 2^n
-const SLAB_MASK: u64 = SLAB_SIZE as u64 - 1;
+/// The slab size used by `BufFile::new`/`with_capacity`. Each `BufFile` can be given its
+/// own slab size via `with_slab_size` or `BufFileBuilder`; it MUST be a power of 2.
+const DEFAULT_SLAB_SIZE: usize = 512*1024; // 512kb
 
 const DEFAULT_CAPACITY: usize = 16;
 
+/// The raw storage backing a `Slab`: either a plain in-memory buffer filled lazily by
+/// `read`/`write`, or (with the `mmap` feature) a direct memory map of the slab's window
+/// of the file.
+enum SlabData {
+    Buffered(Box<[u8]>),
+    #[cfg(feature = "mmap")]
+    Mapped(MmapMut),
+}
+
+impl SlabData {
+    fn as_slice(&self) -> &[u8] {
+        match *self {
+            SlabData::Buffered(ref data) => data,
+            #[cfg(feature = "mmap")]
+            SlabData::Mapped(ref mmap) => mmap,
+        }
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        match *self {
+            SlabData::Buffered(ref mut data) => data,
+            #[cfg(feature = "mmap")]
+            SlabData::Mapped(ref mut mmap) => mmap,
+        }
+    }
+}
+
 /// A struct representing a section of a file
 struct Slab {
     /// The data
-    data: Box<[u8]>,
+    data: SlabData,
     bytes_used: usize,
     /// Has the slab been written to, and not written to disk?
     dirty: bool
 }
 
 impl Slab {
-    /// Creates a new slab, drawing it's data from the given file at the given location
-    /// Location should be at the beginning of a slab (e.g. a multiple of `SLAB_SIZE`)
-    fn new() -> Slab {
+    /// Creates a new, empty slab of `slab_size` bytes. Nothing is read from the file here;
+    /// the data is filled in lazily by `read`/`write`.
+    fn new(slab_size: usize) -> Slab {
         let data = if cfg!(debug_assertions) {
-            vec![0u8; SLAB_SIZE]
+            vec![0u8; slab_size]
         } else {
-            let mut vec = Vec::with_capacity(SLAB_SIZE);
+            let mut vec = Vec::with_capacity(slab_size);
             unsafe {
-                vec.set_len(SLAB_SIZE);
+                vec.set_len(slab_size);
             }
             vec
         };
         Slab {
-            data: data.into_boxed_slice(),
+            data: SlabData::Buffered(data.into_boxed_slice()),
             bytes_used: 0,
             dirty: false
         }
     }
 
+    /// Creates a slab whose data is a memory map of the file, already covering
+    /// `bytes_used` bytes of logical data.
+    #[cfg(feature = "mmap")]
+    fn mapped(mmap: MmapMut, bytes_used: usize) -> Slab {
+        Slab {
+            data: SlabData::Mapped(mmap),
+            bytes_used: bytes_used,
+            dirty: false
+        }
+    }
+
     fn flush<F: Write + Seek>(&mut self, f: &mut F, offset: u64) -> io::Result<()> {
         if self.dirty {
-            f.seek(SeekFrom::Start(offset))?;
-            f.write_all(&self.data)?;
+            match self.data {
+                SlabData::Buffered(ref data) => {
+                    // Only the bytes actually known to be valid are flushed - writing the
+                    // whole slab_size buffer would pad a partially-filled trailing slab
+                    // with garbage/zeroed bytes past the logical end of the file.
+                    f.seek(SeekFrom::Start(offset))?;
+                    f.write_all(&data[..self.bytes_used])?;
+                }
+                #[cfg(feature = "mmap")]
+                SlabData::Mapped(ref mut mmap) => {
+                    mmap.flush_range(0, self.bytes_used)?;
+                }
+            }
             self.dirty = false;
         }
         Ok(())
     }
 }
 
-pub struct BufFile<F: Write + Read + Seek> {
+pub struct BufFile<F: Write + Read + Seek + 'static> {
     slabs: LruCache<usize, Slab>,
     /// The file to be written to and read from
     file: Option<F>,
     /// Represents the current location of the cursor.
     /// This does not reflect the actual location of the cursor in the file.
     cursor: u64,
+    /// The logical length of the file, including any unflushed dirty slabs
+    /// that have extended it beyond what's currently on disk.
+    len: u64,
+    /// The size, in bytes, of each slab. Always a power of two.
+    slab_size: usize,
+    /// Whether new slabs should be memory-mapped rather than buffered. Only ever set by
+    /// `BufFile::mmap`, and only takes effect for slabs backed by a real `File`.
+    #[cfg(feature = "mmap")]
+    use_mmap: bool,
 }
 
-impl<F: Write + Read + Seek> BufFile<F> {
+impl<F: Write + Read + Seek + 'static> BufFile<F> {
     /// Creates a new BufFile.
     pub fn new(file: F) -> io::Result<BufFile<F>> {
         Self::with_capacity(file, DEFAULT_CAPACITY)
     }
 
     /// Creates a new BufFile with the specified number of slabs.
-    pub fn with_capacity(mut file: F, capacity: usize) -> io::Result<BufFile<F>> {
+    pub fn with_capacity(file: F, capacity: usize) -> io::Result<BufFile<F>> {
+        Self::with_slab_size(file, DEFAULT_SLAB_SIZE, capacity)
+    }
+
+    /// Creates a new BufFile with the given per-slab size (in bytes, must be a power of
+    /// two) and number of cached slabs. Lets callers tune memory/throughput for their
+    /// access pattern - small slabs for scattered random reads, large slabs for
+    /// sequential access - without recompiling the crate.
+    pub fn with_slab_size(mut file: F, slab_size: usize, capacity: usize) -> io::Result<BufFile<F>> {
+        if !slab_size.is_power_of_two() {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "slab_size must be a power of two"));
+        }
         let current = file.seek(SeekFrom::Current(0))?;
+        let len = file.seek(SeekFrom::End(0))?;
+        file.seek(SeekFrom::Start(current))?;
         Ok(BufFile {
             slabs: LruCache::new(capacity),
             file: Some(file),
             cursor: current,      // Since the cursor is at the start of the file
+            len: len,
+            slab_size: slab_size,
+            #[cfg(feature = "mmap")]
+            use_mmap: false,
         })
     }
 
@@ -85,43 +162,133 @@ impl<F: Write + Read + Seek> BufFile<F> {
         Ok(self.file.take().unwrap())
     }
 
-    /*
-    /// Change the number of slabs to the desired number. If there are more slabs
-    /// currently loaded than `num_slabs`, then the least frequently used slab(s)
-    /// will be removed until it is equal. Every removed slab gets written to disk,
-    /// creating the possibility for I/O errors.
-    pub fn set_slabs(&mut self, num_slabs: usize) -> Result<(), Error> {
-        // There isn't anything logical to actually do here, so just return
-        if num_slabs == 0 { return Ok(()) }
-        if num_slabs >= self.dat.len() {
-            self.slabs = num_slabs;
-            return Ok(())
-        }
-        while self.dat.len() > num_slabs {
-            let mut min = 0;
-            for i in 0..self.slabs {
-                if self.dat[min].uses == 1 {
-                    min = i;
-                    // The minimum number of reads is 1, so if we encounter 1 just break.
-                    break;
-                }
-                if self.dat[min].uses > self.dat[i].uses {
-                    min = i;
-                }
-            }
-            self.dat[min].write(self.file.as_mut().unwrap())?;
-            let _ = self.dat.swap_remove(min);
+    /// Creates a `BufFile` whose slabs are memory-mapped windows of the file instead of
+    /// being filled by explicit `read`/`write` calls, which some consumers (e.g.
+    /// memmap-backed record stores) prefer for random access over large files.
+    ///
+    /// This probes the mapping up front; if it fails (the underlying store isn't really
+    /// backed by a `File`, or the platform refuses the mapping), slabs quietly fall back
+    /// to the regular buffered path for the rest of this `BufFile`'s life.
+    #[cfg(feature = "mmap")]
+    pub fn mmap(file: F) -> io::Result<BufFile<F>> {
+        let mut buf_file = Self::with_capacity(file, DEFAULT_CAPACITY)?;
+        buf_file.use_mmap = buf_file.probe_mmap().is_ok();
+        Ok(buf_file)
+    }
+
+    /// Attempts to grow the file to at least one slab and map it, purely to check whether
+    /// mapping is possible at all; the probe mapping is dropped immediately and the file's
+    /// original length is restored.
+    #[cfg(feature = "mmap")]
+    fn probe_mmap(&mut self) -> io::Result<()> {
+        let original_len = self.len;
+        let slab_size = self.slab_size;
+        let file = match (self.file.as_mut().unwrap() as &mut dyn Any).downcast_mut::<File>() {
+            Some(file) => file,
+            None => return Err(io::Error::new(ErrorKind::Other, "not backed by a real file")),
+        };
+        if file.metadata()?.len() < slab_size as u64 {
+            file.set_len(slab_size as u64)?;
+        }
+        let result = unsafe { MmapOptions::new().len(slab_size).map_mut(&*file) };
+        file.set_len(original_len)?;
+        result.map(|_| ())
+    }
+
+    /// Changes the number of slabs kept in memory at once. If there are more slabs
+    /// currently loaded than `num_slabs`, the least recently used slab(s) are flushed to
+    /// disk and evicted until it is equal.
+    pub fn set_slabs(&mut self, num_slabs: usize) -> io::Result<()> {
+        if num_slabs == 0 {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "must keep at least one slab cached"));
         }
-        self.slabs = num_slabs;
+        while self.slabs.len() > num_slabs {
+            let (old_idx, mut old_slab) = self.slabs.remove_lru().expect("len() > num_slabs implies a slab exists");
+            let old_offset = old_idx as u64 * self.slab_size as u64;
+            old_slab.flush(self.file.as_mut().unwrap(), old_offset)?;
+        }
+        let mut kept = Vec::with_capacity(self.slabs.len());
+        while let Some(entry) = self.slabs.remove_lru() {
+            kept.push(entry);
+        }
+        let mut resized = LruCache::new(num_slabs);
+        // remove_lru() yields least-recently-used first; re-inserting in that same order
+        // puts the most-recently-used slab back in last, preserving the relative LRU order.
+        for (idx, slab) in kept.into_iter() {
+            resized.insert(idx, slab);
+        }
+        self.slabs = resized;
         Ok(())
     }
-    */
 
     /// Returns the current cursor_loc
     pub fn cursor_loc(&self) -> u64 {
         self.cursor
     }
 
+    /// Returns the current logical length of the file.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Opens a hole of `data.len()` bytes at `at`, shifting everything at or after `at`
+    /// to the right, then writes `data` into the hole. Leaves the cursor positioned just
+    /// after the inserted data.
+    ///
+    /// The shift is done back to front, a slab at a time, so that bytes are always read
+    /// before the region they're about to be copied into is overwritten.
+    pub fn insert(&mut self, at: u64, data: &[u8]) -> io::Result<()> {
+        if at > self.len {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "insert position past end of file"));
+        }
+        let shift = data.len() as u64;
+        if shift == 0 {
+            self.cursor = at;
+            return Ok(());
+        }
+        let mut chunk = vec![0u8; self.slab_size];
+        let mut tail = self.len;
+        while tail > at {
+            let chunk_len = cmp::min(self.slab_size as u64, tail - at) as usize;
+            let src = tail - chunk_len as u64;
+            self.seek(SeekFrom::Start(src))?;
+            self.read_exact(&mut chunk[..chunk_len])?;
+            self.seek(SeekFrom::Start(src + shift))?;
+            self.write_all(&chunk[..chunk_len])?;
+            tail = src;
+        }
+        self.seek(SeekFrom::Start(at))?;
+        self.write_all(data)?;
+        Ok(())
+    }
+
+    /// Streams the rest of this `BufFile` (from the current cursor to EOF) directly into
+    /// `dst`, writing straight out of each loaded slab instead of round-tripping through a
+    /// temporary stack buffer the way `io::copy` would. Returns the total bytes copied.
+    pub fn copy_to<W: Write>(&mut self, dst: &mut W) -> io::Result<u64> {
+        let mut total = 0u64;
+        loop {
+            let len = {
+                let buf = self.fill_buf()?;
+                if buf.is_empty() {
+                    break;
+                }
+                // write_all already retries on ErrorKind::Interrupted.
+                dst.write_all(buf)?;
+                buf.len()
+            };
+            self.consume(len);
+            total += len as u64;
+        }
+        Ok(total)
+    }
+
+    /// Turns a file offset into a slab index (since `slab_size` is a power of two, this
+    /// is equivalent to dividing the offset by `slab_size`).
+    fn idx_from_offset(&self, offset: u64) -> usize {
+        (offset / self.slab_size as u64) as usize
+    }
+
     /// Find the existing slab, or retrieve it manually
     fn fetch_slab(&mut self, idx: usize) -> io::Result<(&mut Slab, &mut F)> {
         if self.slabs.contains_key(&idx) {
@@ -135,115 +302,377 @@ impl<F: Write + Read + Seek> BufFile<F> {
     /// the least frequently used slab to disk and load the new one into self.dat,
     /// then return Ok(index), index being an index for self.dat.
     fn add_slab(&mut self, idx: usize) -> io::Result<(&mut Slab, &mut F)> {
-        let mut file = self.file.as_mut().unwrap();
-        let slab = if self.slabs.len() == self.slabs.capacity() {
+        if self.slabs.len() == self.slabs.capacity() {
             let (old_idx, mut old_slab) = self.slabs.remove_lru().expect("Capacity should never be 0");
-            let old_offset = old_idx as u64 * SLAB_SIZE as u64;
-            old_slab.flush(&mut file, old_offset)?;
-            old_slab.bytes_used = 0;
-            old_slab
-        } else {
-            Slab::new()
-        };
+            let old_offset = old_idx as u64 * self.slab_size as u64;
+            old_slab.flush(self.file.as_mut().unwrap(), old_offset)?;
+        }
+        let offset = idx as u64 * self.slab_size as u64;
+        let slab = self.new_slab(offset)?;
         self.slabs.insert(idx, slab);
+        let file = self.file.as_mut().unwrap();
         let slab = self.slabs.get_mut(&idx).expect("Value should exist, was just inserted");
         Ok((slab, file))
     }
+
+    /// Creates a fresh slab for the window starting at `offset`. When this `BufFile` was
+    /// constructed via `mmap` and the backing store is still a real `File`, maps only the
+    /// bytes that already exist in that window (so a tail slab gets a shorter mapping
+    /// instead of the file being grown to a full slab just to back it); otherwise, or if
+    /// `offset` is already at or past the logical end of the file, allocates the regular
+    /// in-memory buffer filled lazily by `read`/`write`.
+    #[cfg(feature = "mmap")]
+    fn new_slab(&mut self, offset: u64) -> io::Result<Slab> {
+        if self.use_mmap {
+            let len = self.len;
+            let slab_size = self.slab_size;
+            let map_len = cmp::min(slab_size as u64, len.saturating_sub(offset)) as usize;
+            if map_len > 0 {
+                if let Some(file) = (self.file.as_mut().unwrap() as &mut dyn Any).downcast_mut::<File>() {
+                    let mmap = unsafe { MmapOptions::new().offset(offset).len(map_len).map_mut(&*file)? };
+                    return Ok(Slab::mapped(mmap, map_len));
+                }
+            }
+        }
+        Ok(Slab::new(self.slab_size))
+    }
+
+    #[cfg(not(feature = "mmap"))]
+    fn new_slab(&mut self, _offset: u64) -> io::Result<Slab> {
+        Ok(Slab::new(self.slab_size))
+    }
 }
 
+/// Backing stores that support shrinking to a given length. `remove` needs this to
+/// truncate the file once the trailing data has been shifted down, which isn't something
+/// `Write + Read + Seek` alone can express.
+pub trait Truncate {
+    fn set_len(&mut self, size: u64) -> io::Result<()>;
+}
 
-fn idx_from_offset(offset: u64) -> usize {
-    (offset / SLAB_SIZE as u64) as usize
+impl Truncate for File {
+    fn set_len(&mut self, size: u64) -> io::Result<()> {
+        File::set_len(self, size)
+    }
 }
 
-impl<F: Write + Read + Seek> Read for BufFile<F> {
+impl<F: Write + Read + Seek + 'static + Truncate> BufFile<F> {
+    /// Closes the hole `range`, shifting everything after `range.end` left by the range's
+    /// length and truncating the file to the new logical length.
+    ///
+    /// The shift is done front to back, a slab at a time, so that bytes are always read
+    /// before the region they're about to be copied into is overwritten. The shifted data
+    /// passes through the slab cache, so once the shift is done every cached slab is
+    /// flushed and then dropped before the file is truncated.
+    pub fn remove(&mut self, range: Range<u64>) -> io::Result<()> {
+        if range.start > range.end || range.end > self.len {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "invalid remove range"));
+        }
+        let shift = range.end - range.start;
+        if shift == 0 {
+            return Ok(());
+        }
+        let mut chunk = vec![0u8; self.slab_size];
+        let mut src = range.end;
+        let mut dst = range.start;
+        while src < self.len {
+            let chunk_len = cmp::min(self.slab_size as u64, self.len - src) as usize;
+            self.seek(SeekFrom::Start(src))?;
+            self.read_exact(&mut chunk[..chunk_len])?;
+            self.seek(SeekFrom::Start(dst))?;
+            self.write_all(&chunk[..chunk_len])?;
+            src += chunk_len as u64;
+            dst += chunk_len as u64;
+        }
+        let new_len = self.len - shift;
+        self.flush()?;
+        self.slabs.clear();
+        self.file.as_mut().unwrap().set_len(new_len)?;
+        self.len = new_len;
+        if self.cursor > new_len {
+            self.cursor = new_len;
+        }
+        Ok(())
+    }
+}
+
+/// Builds a `BufFile` with a configurable slab size and cache capacity, for callers who
+/// want to tune memory/throughput for their access pattern without recompiling the crate.
+pub struct BufFileBuilder {
+    slab_size: usize,
+    capacity: usize,
+}
+
+impl Default for BufFileBuilder {
+    fn default() -> BufFileBuilder {
+        BufFileBuilder {
+            slab_size: DEFAULT_SLAB_SIZE,
+            capacity: DEFAULT_CAPACITY,
+        }
+    }
+}
+
+impl BufFileBuilder {
+    pub fn new() -> BufFileBuilder {
+        BufFileBuilder::default()
+    }
+
+    /// Sets the size, in bytes, of each slab. Must be a power of two; validated when
+    /// `build` is called.
+    pub fn slab_size(mut self, slab_size: usize) -> BufFileBuilder {
+        self.slab_size = slab_size;
+        self
+    }
+
+    /// Sets the number of slabs kept in memory at once.
+    pub fn capacity(mut self, capacity: usize) -> BufFileBuilder {
+        self.capacity = capacity;
+        self
+    }
+
+    pub fn build<F: Write + Read + Seek + 'static>(self, file: F) -> io::Result<BufFile<F>> {
+        BufFile::with_slab_size(file, self.slab_size, self.capacity)
+    }
+}
+
+impl<F: Write + Read + Seek + 'static> Read for BufFile<F> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         let cursor = self.cursor;
-        let idx = idx_from_offset(cursor);
-        let slab_start = idx as u64 * SLAB_SIZE as u64;
+        let idx = self.idx_from_offset(cursor);
+        let slab_start = idx as u64 * self.slab_size as u64;
         let cursor_offset = (cursor - slab_start) as usize;
         let len = {
             let (slab, file) = self.fetch_slab(idx)?;
             while cursor_offset >= slab.bytes_used {
-                let bytes_read = file.read(&mut slab.data[slab.bytes_used..])?;
+                // The backing file's own cursor is shared across every slab and isn't
+                // tracked anywhere else, so it has to be repositioned before each read -
+                // otherwise a seek to a different slab leaves it wherever the last
+                // read/write on *any* slab happened to leave it.
+                file.seek(SeekFrom::Start(slab_start + slab.bytes_used as u64))?;
+                let bytes_read = file.read(&mut slab.data.as_mut_slice()[slab.bytes_used..])?;
                 if bytes_read == 0 {
                     break;
                 }
                 slab.bytes_used += bytes_read;
             }
-            let len = slab.bytes_used - cursor_offset;
-            buf[..len].copy_from_slice(&slab.data[cursor_offset..slab.bytes_used]);
+            // cursor_offset can land past bytes_used when the cursor has been seeked
+            // beyond the logical end of the file; clamp so such reads return 0 instead
+            // of underflowing.
+            let clamped_offset = cursor_offset.min(slab.bytes_used);
+            // Never copy out more than the caller's buffer can hold - the slab may
+            // have more data available than `buf` has room for.
+            let len = cmp::min(buf.len(), slab.bytes_used - clamped_offset);
+            buf[..len].copy_from_slice(&slab.data.as_slice()[clamped_offset..clamped_offset + len]);
             len
         };
 
         self.cursor += len as u64;
         Ok(len)
     }
+
+    /// Walks the slices in order, filling each one across as many slabs as it takes
+    /// (a scalar `read` only ever fills up to the end of the current slab), rather than
+    /// the default which only fills the first non-empty buffer. Only stops early on a
+    /// true EOF, i.e. a `read` that returns 0.
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut]) -> io::Result<usize> {
+        let mut total = 0;
+        for buf in bufs.iter_mut() {
+            let mut filled = 0;
+            while filled < buf.len() {
+                let n = self.read(&mut buf[filled..])?;
+                if n == 0 {
+                    break;
+                }
+                filled += n;
+            }
+            total += filled;
+            if filled < buf.len() {
+                break;
+            }
+        }
+        Ok(total)
+    }
 }
 
-impl<F: Write + Read + Seek> Write for BufFile<F> {
+impl<F: Write + Read + Seek + 'static> Write for BufFile<F> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         let cursor = self.cursor;
-        let idx = idx_from_offset(cursor);
-        let slab_start = idx as u64 * SLAB_SIZE as u64;
+        let idx = self.idx_from_offset(cursor);
+        let slab_size = self.slab_size;
+        let slab_start = idx as u64 * slab_size as u64;
         let cursor_offset = (cursor - slab_start) as usize;
         let len = {
             let (slab, file) = self.fetch_slab(idx)?;
             slab.dirty = true;
+            // A mapped tail slab's buffer can be shorter than slab_size (it's only ever
+            // mapped out to the file's existing length), so the write is bounded by the
+            // buffer's actual capacity rather than assuming a full slab_size is available.
+            let cap = slab.data.as_slice().len();
+            let read_target = cmp::min(cursor_offset, cap);
             // we still need to read up until the write location
-            while cursor_offset > slab.bytes_used {
-                let bytes_read = file.read(&mut slab.data[slab.bytes_used..cursor_offset])?;
+            while slab.bytes_used < read_target {
+                // See the matching comment in `Read::read`: reposition before every read,
+                // since the backing file's cursor is shared across all slabs.
+                file.seek(SeekFrom::Start(slab_start + slab.bytes_used as u64))?;
+                let bytes_read = file.read(&mut slab.data.as_mut_slice()[slab.bytes_used..read_target])?;
                 if bytes_read == 0 {
                     break;
                 }
                 slab.bytes_used += bytes_read;
             }
-            let len = cmp::min(buf.len(), SLAB_SIZE - cursor_offset);
-            slab.data[cursor_offset..cursor_offset + len].copy_from_slice(&buf[..len]);
+            let len = cmp::min(buf.len(), cap.saturating_sub(cursor_offset));
+            if len > 0 {
+                slab.data.as_mut_slice()[cursor_offset..cursor_offset + len].copy_from_slice(&buf[..len]);
+                // The bytes we just wrote are now known-good, even past whatever was
+                // previously read in from disk.
+                slab.bytes_used = cmp::max(slab.bytes_used, cursor_offset + len);
+            }
             len
         };
         self.cursor += len as u64;
+        if self.cursor > self.len {
+            self.len = self.cursor;
+        }
         Ok(len)
     }
 
     fn flush(&mut self) -> io::Result<()> {
+        let slab_size = self.slab_size as u64;
         let mut file = self.file.as_mut().unwrap();
         for (&idx, slab) in self.slabs.iter_mut() {
-            let offset = idx as u64 * SLAB_SIZE as u64;
+            let offset = idx as u64 * slab_size;
             slab.flush(&mut file, offset)?;
         }
         file.flush()
     }
+
+    /// Walks the slices in order, writing each one across as many slabs as it takes
+    /// (a scalar `write` only ever fills up to the end of the current slab), rather than
+    /// the default which only writes the first non-empty buffer. Only stops early if a
+    /// `write` makes no progress.
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        let mut total = 0;
+        for buf in bufs.iter() {
+            let mut written = 0;
+            while written < buf.len() {
+                let n = self.write(&buf[written..])?;
+                if n == 0 {
+                    break;
+                }
+                written += n;
+            }
+            total += written;
+            if written < buf.len() {
+                break;
+            }
+        }
+        Ok(total)
+    }
 }
 
-impl<F: Write + Read + Seek> Seek for BufFile<F> {
+impl<F: Write + Read + Seek + 'static> Seek for BufFile<F> {
+    /// Seeks against the logical length of the file (`self.len`), which accounts for any
+    /// unflushed dirty slabs that have extended the file beyond what's on disk. Seeking
+    /// to a position before 0 returns an `InvalidInput` error instead of panicking.
     fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
         let new_pos = match pos {
-            SeekFrom::Start(x) => {
-                self.cursor = x;
-                self.cursor
-            },
-            SeekFrom::End(_) => {
-                let file = self.file.as_mut().unwrap();
-                let cursor = file.seek(pos)?;
-                self.cursor = cursor;
-                cursor
-            },
-            SeekFrom::Current(x) => {
-                let cur = self.cursor;
-
-                let cursor =
-                    if x < 0 { cur - (-x) as u64 }
-                    else { cur - x as u64 };
-                self.cursor = cursor;
-                cursor
+            SeekFrom::Start(x) => Some(x),
+            SeekFrom::End(off) => {
+                if off <= 0 {
+                    self.len.checked_sub(off.wrapping_neg() as u64)
+                } else {
+                    self.len.checked_add(off as u64)
+                }
+            }
+            SeekFrom::Current(off) => {
+                if off <= 0 {
+                    self.cursor.checked_sub(off.wrapping_neg() as u64)
+                } else {
+                    self.cursor.checked_add(off as u64)
+                }
             }
         };
+        let new_pos = new_pos.ok_or_else(|| {
+            io::Error::new(ErrorKind::InvalidInput, "invalid seek to a negative or overflowing position")
+        })?;
+        self.cursor = new_pos;
         Ok(new_pos)
     }
 }
 
-impl<F: Read + Write + Seek> Drop for BufFile<F> {
+impl<F: Write + Read + Seek + 'static> BufRead for BufFile<F> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        let cursor = self.cursor;
+        let idx = self.idx_from_offset(cursor);
+        let slab_start = idx as u64 * self.slab_size as u64;
+        let cursor_offset = (cursor - slab_start) as usize;
+        let (slab, file) = self.fetch_slab(idx)?;
+        while cursor_offset >= slab.bytes_used {
+            // See the matching comment in `Read::read`: reposition before every read,
+            // since the backing file's cursor is shared across all slabs.
+            file.seek(SeekFrom::Start(slab_start + slab.bytes_used as u64))?;
+            let bytes_read = file.read(&mut slab.data.as_mut_slice()[slab.bytes_used..])?;
+            if bytes_read == 0 {
+                break;
+            }
+            slab.bytes_used += bytes_read;
+        }
+        // cursor_offset can land past bytes_used when the cursor has been seeked
+        // beyond the logical end of the file; clamp so such reads return an empty
+        // slice instead of panicking.
+        let clamped_offset = cursor_offset.min(slab.bytes_used);
+        Ok(&slab.data.as_slice()[clamped_offset..slab.bytes_used])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.cursor += amt as u64;
+    }
+
+    /// A returned slab slice only ever covers the remainder of the current slab, so this
+    /// loops across slab boundaries until `byte` is found or EOF is reached.
+    fn read_until(&mut self, byte: u8, buf: &mut Vec<u8>) -> io::Result<usize> {
+        let mut total = 0;
+        loop {
+            let used = {
+                let available = self.fill_buf()?;
+                match available.iter().position(|&b| b == byte) {
+                    Some(i) => {
+                        buf.extend_from_slice(&available[..=i]);
+                        self.consume(i + 1);
+                        total += i + 1;
+                        return Ok(total);
+                    }
+                    None => {
+                        buf.extend_from_slice(available);
+                        available.len()
+                    }
+                }
+            };
+            self.consume(used);
+            total += used;
+            if used == 0 {
+                return Ok(total);
+            }
+        }
+    }
+
+    fn read_line(&mut self, buf: &mut String) -> io::Result<usize> {
+        let mut bytes = Vec::new();
+        let read = self.read_until(b'\n', &mut bytes)?;
+        // Bytes that aren't valid UTF-8 are still consumed from the stream (read_until
+        // has already advanced the cursor past them), but - matching std's read_line -
+        // nothing is appended to `buf` on this error path.
+        match str::from_utf8(&bytes) {
+            Ok(s) => {
+                buf.push_str(s);
+                Ok(read)
+            }
+            Err(_) => Err(io::Error::new(ErrorKind::InvalidData, "stream did not contain valid UTF-8")),
+        }
+    }
+}
+
+impl<F: Read + Write + Seek + 'static> Drop for BufFile<F> {
     /// Write all of the slabs to disk before closing the file.
      fn drop(&mut self) {
          if self.file.is_none() { return }
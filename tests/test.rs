@@ -4,7 +4,7 @@ extern crate tempfile;
 
 use tempfile::tempfile;
 use std::io::prelude::*;
-use std::io::{self, SeekFrom};
+use std::io::{self, SeekFrom, IoSlice, IoSliceMut};
 use std::time::SystemTime;
 
 use rand::{Rng, SeedableRng};
@@ -22,29 +22,135 @@ fn test_seek_past_end_read() {
 }
 
 #[test]
-#[should_panic]
 fn test_seek_end_error() {
     let mut test_file = BufFile::new(tempfile().unwrap()).unwrap();
-    test_file.seek(SeekFrom::End(1)).unwrap();
+    assert!(test_file.seek(SeekFrom::End(-1)).is_err());
 }
 
 #[test]
-#[should_panic]
 fn test_seek_current_error() {
     let mut test_file = BufFile::new(tempfile().unwrap()).unwrap();
-    test_file.seek(SeekFrom::Current(1)).unwrap();
+    assert!(test_file.seek(SeekFrom::Current(-1)).is_err());
+}
+
+// This test verifies that insert/remove round-trip correctly across a slab boundary,
+// i.e. that the backing file is read back correctly after the BufFile's own cursor has
+// been seeked around by the shift loop.
+#[test]
+fn test_insert_remove_roundtrip() {
+    let mut buf_file = BufFile::with_slab_size(tempfile().unwrap(), 16, 4).unwrap();
+    let original: Vec<u8> = (0..64u8).collect();
+    buf_file.write_all(&original).unwrap();
+    buf_file.flush().unwrap();
+
+    buf_file.insert(10, b"INSERTED").unwrap();
+    let mut expected = original.clone();
+    expected.splice(10..10, b"INSERTED".iter().cloned());
+    buf_file.seek(SeekFrom::Start(0)).unwrap();
+    let mut contents = Vec::new();
+    buf_file.read_to_end(&mut contents).unwrap();
+    assert_eq!(contents, expected);
+
+    buf_file.remove(10..20).unwrap();
+    expected.splice(10..20, std::iter::empty());
+    buf_file.seek(SeekFrom::Start(0)).unwrap();
+    let mut contents = Vec::new();
+    buf_file.read_to_end(&mut contents).unwrap();
+    assert_eq!(contents, expected);
+}
+
+// This test verifies that read_line starts from the BufFile's logical cursor after it has
+// been seeked around, and that a line crossing a slab boundary is read whole.
+#[test]
+fn test_seek_then_read_line_across_slab_boundary() {
+    let mut buf_file = BufFile::with_slab_size(tempfile().unwrap(), 16, 4).unwrap();
+    buf_file.write_all(b"first line\nsecond line crossing a slab boundary\nthird\n").unwrap();
+
+    // Seek around first; these seeks only move the BufFile's own logical cursor, and
+    // the data is still held in the cached slabs.
+    buf_file.seek(SeekFrom::End(0)).unwrap();
+    buf_file.seek(SeekFrom::Start(0)).unwrap();
+
+    let mut line = String::new();
+    buf_file.read_line(&mut line).unwrap();
+    assert_eq!(line, "first line\n");
+
+    let mut line2 = String::new();
+    buf_file.read_line(&mut line2).unwrap();
+    assert_eq!(line2, "second line crossing a slab boundary\n");
+}
+
+// This test verifies that copy_to keeps working when the backing file's own cursor has
+// been left away from the slab being read, and that it crosses slab boundaries.
+#[test]
+fn test_seek_then_copy_to_across_slab_boundary() {
+    let mut buf_file = BufFile::with_slab_size(tempfile().unwrap(), 16, 4).unwrap();
+    let data: Vec<u8> = (0..200u8).cycle().take(200).collect();
+    buf_file.write_all(&data).unwrap();
+
+    // The 200 bytes span 13 slabs with only 4 cached, so write_all has already flushed
+    // evicted slabs and left the backing file's cursor past the first slab. The seeks
+    // below only move the BufFile's own logical cursor.
+    buf_file.seek(SeekFrom::Start(0)).unwrap();
+    buf_file.seek(SeekFrom::End(0)).unwrap();
+    buf_file.seek(SeekFrom::Start(10)).unwrap();
+
+    let mut copied = Vec::new();
+    let n = buf_file.copy_to(&mut copied).unwrap();
+    assert_eq!(n as usize, data.len() - 10);
+    assert_eq!(copied, &data[10..]);
+}
+
+// This test verifies that write_vectored/read_vectored each fill a single slice all the
+// way across several slab boundaries, rather than stopping short at the first one.
+#[test]
+fn test_vectored_io_across_slab_boundaries() {
+    let mut buf_file = BufFile::with_slab_size(tempfile().unwrap(), 16, 4).unwrap();
+
+    let part_a = [1u8; 10];
+    let part_b = [2u8; 50]; // spans several 16-byte slabs on its own
+    let bufs = [IoSlice::new(&part_a), IoSlice::new(&part_b)];
+    let written = buf_file.write_vectored(&bufs).unwrap();
+    assert_eq!(written, part_a.len() + part_b.len());
+
+    buf_file.seek(SeekFrom::Start(0)).unwrap();
+    let mut read_a = [0u8; 10];
+    let mut read_b = [0u8; 50];
+    let mut read_bufs = [IoSliceMut::new(&mut read_a), IoSliceMut::new(&mut read_b)];
+    let read = buf_file.read_vectored(&mut read_bufs).unwrap();
+    assert_eq!(read, part_a.len() + part_b.len());
+    assert_eq!(read_a, part_a);
+    assert_eq!(read_b, part_b);
+}
+
+// This test verifies that shrinking the slab cache with set_slabs flushes the evicted
+// slabs to disk, so the data is still there when read back afterwards.
+#[test]
+fn test_set_slabs_shrink_roundtrip() {
+    let mut buf_file = BufFile::with_slab_size(tempfile().unwrap(), 16, 8).unwrap();
+    let data: Vec<u8> = (0..128u8).collect();
+    buf_file.write_all(&data).unwrap();
+
+    // All 8 slabs are now cached (dirty, unflushed); shrinking should flush the ones
+    // that no longer fit instead of losing their contents.
+    buf_file.set_slabs(2).unwrap();
+
+    buf_file.seek(SeekFrom::Start(0)).unwrap();
+    let mut contents = Vec::new();
+    buf_file.read_to_end(&mut contents).unwrap();
+    assert_eq!(contents, data);
 }
 
 // This test verifies that the BufFile behaves exactly like a file when reading, writing, and seeking.
 // It randomly seeks and writes data, and verifies everything is completely equal with the actual file.
 #[test]
 fn test_file_buffer() {
-    struct CheckFiles<F: Read + Write + Seek> {
+    struct CheckFiles<F: Read + Write + Seek + 'static> {
         real_file: F,
         buf_file: BufFile<F>,
     }
 
-    impl<F: Read + Write + Seek> Seek for CheckFiles<F> {
+    impl<F: Read + Write + Seek + 'static> Seek for CheckFiles<F> {
         fn seek(&mut self, from: SeekFrom) -> io::Result<u64> {
             let real = self.real_file.seek(from);
             let buf = self.buf_file.seek(from);
@@ -53,7 +159,7 @@ fn test_file_buffer() {
         }
     }
 
-    impl<F: Read + Write + Seek> Read for CheckFiles<F> {
+    impl<F: Read + Write + Seek + 'static> Read for CheckFiles<F> {
         fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
             let mut other_buf = vec![0u8; buf.len()];
             let real = self.real_file.read(buf);
@@ -66,7 +172,7 @@ fn test_file_buffer() {
         }
     }
 
-    impl<F: Read + Write + Seek> Write for CheckFiles<F> {
+    impl<F: Read + Write + Seek + 'static> Write for CheckFiles<F> {
         fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
             let real = self.real_file.write(buf);
             let buffered = self.buf_file.write(buf);